@@ -1,68 +1,131 @@
 use std::{
-    alloc::Layout, fmt, future::Future, panic::{self, AssertUnwindSafe}, pin::Pin, ptr::{self, NonNull}, task::{Context, Poll}
+    alloc::{self, Layout}, fmt, future::Future, panic::{self, AssertUnwindSafe}, pin::Pin, ptr::{self, NonNull}, task::{Context, Poll}
 };
 
-/// A reusable `Pin<Box<dyn Future<Output = T> + Send>>`.
+/// Error returned when the global allocator fails to satisfy an allocation
+/// request made by [`ReusableBoxFuture::try_new`],
+/// [`ReusableBoxFuture::try_new_set`], or their [`LocalReusableBoxFuture`]
+/// equivalents.
+#[derive(Debug)]
+pub struct AllocError;
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("memory allocation failed")
+    }
+}
+
+impl std::error::Error for AllocError {}
+
+/// The pointer-swap machinery shared by [`ReusableBoxFuture`] and
+/// [`LocalReusableBoxFuture`].
 ///
-/// This type lets you replace the future stored in the box without
-/// reallocating when the size and alignment permits this.
-pub struct ReusableBoxFuture<T> {
-    boxed: NonNull<dyn Future<Output = T>>,
-    layout: Layout,
+/// This type intentionally has no opinion on whether the stored future is
+/// `Send`: [`ReusableBoxFuture`]'s public constructors and setters require
+/// it and [`LocalReusableBoxFuture`]'s do not; this type's storage does not. That's what lets the two
+/// wrappers share every unsafe line here instead of duplicating it.
+struct Inner<'a, T> {
+    boxed: NonNull<dyn Future<Output = T> + 'a>,
+    /// The layout the backing allocation was actually made with. This is
+    /// always at least as large (and at least as aligned) as the layout of
+    /// whatever future is currently stored behind `boxed`, since `set`/
+    /// `try_set` reuse the allocation for any future that fits rather than
+    /// requiring an exact layout match.
+    alloc_layout: Layout,
+    /// Whether the most recent call to `poll` returned `Poll::Ready`. Reset
+    /// to `false` whenever a new future is installed.
+    finished: bool,
 }
 
-impl<T> ReusableBoxFuture<T> {
-    /// Create a new `ReusableBoxFuture<T>` containing the provided future.
-    pub fn new<F>(future: F) -> Self
+impl<'a, T> Inner<'a, T> {
+    fn new<F>(future: F) -> Self
     where
-        F: Future<Output = T> + 'static,
+        F: Future<Output = T> + 'a,
     {
         let layout = Layout::for_value(&future);
-        let boxed: Box<dyn Future<Output = T>> = Box::new(future);
+        let boxed: Box<dyn Future<Output = T> + 'a> = Box::new(future);
 
         let boxed = Box::into_raw(boxed);
 
         // SAFETY: Box::into_raw does not return null pointers.
         let boxed = unsafe { NonNull::new_unchecked(boxed) };
 
-        Self { boxed, layout }
+        Self {
+            boxed,
+            alloc_layout: layout,
+            finished: false,
+        }
     }
 
-    /// Replace the future currently stored in this box.
-    ///
-    /// This reallocates if and only if the layout of the provided future is
-    /// different from the layout of the currently stored future.
-    pub fn set<F>(&mut self, future: F)
+    fn try_new<F>(future: F) -> Result<Self, (F, AllocError)>
     where
-        F: Future<Output = T> + 'static,
+        F: Future<Output = T> + 'a,
     {
         let layout = Layout::for_value(&future);
 
-        if layout == self.layout {
-            // SAFETY: We just checked that the layout of F is correct.
+        let raw: *mut F = if layout.size() == 0 {
+            NonNull::<F>::dangling().as_ptr()
+        } else {
+            // SAFETY: `layout` has a non-zero size.
+            let raw = unsafe { alloc::alloc(layout) } as *mut F;
+            if raw.is_null() {
+                return Err((future, AllocError));
+            }
+            raw
+        };
+
+        // SAFETY: `raw` points at freshly allocated memory (or is a dangling
+        // but well-aligned pointer for a zero-sized future) that is not
+        // aliased by anything else.
+        unsafe {
+            ptr::write(raw, future);
+        }
+
+        let boxed: *mut (dyn Future<Output = T> + 'a) = raw;
+
+        // SAFETY: `raw` is non-null, so the unsized pointer is too.
+        let boxed = unsafe { NonNull::new_unchecked(boxed) };
+
+        Ok(Self {
+            boxed,
+            alloc_layout: layout,
+            finished: false,
+        })
+    }
+
+    /// Returns `true` if a value of `layout` can be stored in this box's
+    /// current allocation without reallocating.
+    fn fits(&self, layout: Layout) -> bool {
+        layout.size() <= self.alloc_layout.size()
+            && self.alloc_layout.align().is_multiple_of(layout.align())
+    }
+
+    fn set<F>(&mut self, future: F)
+    where
+        F: Future<Output = T> + 'a,
+    {
+        let layout = Layout::for_value(&future);
+
+        if self.fits(layout) {
+            // SAFETY: We just checked that F fits in the current allocation.
             unsafe {
-                self.set_same_layout(future);
+                self.set_within_capacity(future);
             }
         } else {
             *self = Self::new(future);
         }
     }
 
-    /// Replace the future currently stored in this box.
-    ///
-    /// This function never reallocates, but returns an error if the provided
-    /// future has a different size or alignment from the currently stored
-    /// future.
-    pub fn try_set<F>(&mut self, future: F) -> Result<(), F>
+    fn try_set<F>(&mut self, future: F) -> Result<(), F>
     where
-        F: Future<Output = T> + 'static,
+        F: Future<Output = T> + 'a,
     {
         let layout = Layout::for_value(&future);
 
-        if layout == self.layout {
-            // SAFETY: We just checked that the layout of F is correct.
+        if self.fits(layout) {
+            // SAFETY: We just checked that F fits in the current allocation.
             unsafe {
-                self.set_same_layout(future);
+                self.set_within_capacity(future);
             }
 
             Ok(())
@@ -71,15 +134,36 @@ impl<T> ReusableBoxFuture<T> {
         }
     }
 
-    /// Set the current future.
+    fn try_new_set<F>(&mut self, future: F) -> Result<(), (F, AllocError)>
+    where
+        F: Future<Output = T> + 'a,
+    {
+        let layout = Layout::for_value(&future);
+
+        if self.fits(layout) {
+            // SAFETY: We just checked that F fits in the current allocation.
+            unsafe {
+                self.set_within_capacity(future);
+            }
+
+            Ok(())
+        } else {
+            *self = Self::try_new(future)?;
+
+            Ok(())
+        }
+    }
+
+    /// Overwrite the future currently stored in this box in place, reusing
+    /// the existing allocation.
     ///
     /// # Safety
     ///
-    /// This function requires that the layout of the provided future is the
-    /// same as `self.layout`.
-    unsafe fn set_same_layout<F>(&mut self, future: F)
+    /// This function requires that a value of type `F` fits in `self`'s
+    /// current allocation, i.e. `self.fits(Layout::new::<F>())`.
+    unsafe fn set_within_capacity<F>(&mut self, future: F)
     where
-        F: Future<Output = T> + 'static,
+        F: Future<Output = T> + 'a,
     {
         // Drop the existing future, catching any panics.
         let result = panic::catch_unwind(AssertUnwindSafe(|| {
@@ -87,13 +171,14 @@ impl<T> ReusableBoxFuture<T> {
         }));
 
         // Overwrite the future behind the pointer. This is safe because the
-        // allocation was allocated with the same size and alignment as the type F.
+        // allocation is at least as large and as aligned as the type F.
         let self_ptr: *mut F = self.boxed.as_ptr() as *mut F;
         ptr::write(self_ptr, future);
 
         // Update the vtable of self.boxed. The pointer is not null because we
         // just got it from self.boxed, which is not null.
         self.boxed = NonNull::new_unchecked(self_ptr);
+        self.finished = false;
 
         // If the old future's destructor panicked, resume unwinding.
         match result {
@@ -104,47 +189,610 @@ impl<T> ReusableBoxFuture<T> {
         }
     }
 
-    /// Get a pinned reference to the underlying future.
-    pub fn get_pin(&mut self) -> Pin<&mut (dyn Future<Output = T>)> {
+    fn get_pin(&mut self) -> Pin<&mut (dyn Future<Output = T> + 'a)> {
         unsafe { Pin::new_unchecked(self.boxed.as_mut()) }
     }
 
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<T> {
+        let result = self.get_pin().poll(cx);
+        if result.is_ready() {
+            self.finished = true;
+        }
+        result
+    }
+
+    fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Poll the stored future and, if it has just completed, install `next`
+    /// via `set` before returning the completed value. `next` reuses the
+    /// current allocation if it fits; otherwise the box reallocates.
+    ///
+    /// If the stored future is still pending, `next` is dropped without
+    /// being installed and the stored future is left in place to be polled
+    /// again.
+    fn set_and_return_completed<F>(&mut self, cx: &mut Context<'_>, next: F) -> Poll<T>
+    where
+        F: Future<Output = T> + 'a,
+    {
+        let result = self.poll(cx);
+        if result.is_ready() {
+            self.set(next);
+        }
+        result
+    }
+}
+
+impl<T> Drop for Inner<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.boxed.as_ptr());
+
+            // We can't use `Box::from_raw` here: the allocation may be
+            // larger than the currently stored future (see `fits`), so it
+            // must be freed with the allocation's own layout, not the
+            // layout of whatever future currently lives in it.
+            if self.alloc_layout.size() != 0 {
+                alloc::dealloc(self.boxed.as_ptr() as *mut u8, self.alloc_layout);
+            }
+        }
+    }
+}
+
+/// A reusable `Pin<Box<dyn Future<Output = T> + Send + 'a>>`.
+///
+/// This type lets you replace the future stored in the box without
+/// reallocating, as long as the new future fits in the box's current
+/// allocation.
+///
+/// The stored future must be `Send`. For a single-threaded executor that
+/// wants to store non-`Send` futures, use [`LocalReusableBoxFuture`] instead.
+pub struct ReusableBoxFuture<'a, T>(Inner<'a, T>);
+
+impl<'a, T> ReusableBoxFuture<'a, T> {
+    /// Create a new `ReusableBoxFuture<T>` containing the provided future.
+    pub fn new<F>(future: F) -> Self
+    where
+        F: Future<Output = T> + Send + 'a,
+    {
+        Self(Inner::new(future))
+    }
+
+    /// Create a new `ReusableBoxFuture<T>` containing the provided future,
+    /// without aborting on allocation failure.
+    ///
+    /// If the global allocator fails to satisfy the allocation, the future is
+    /// handed back to the caller alongside an [`AllocError`].
+    pub fn try_new<F>(future: F) -> Result<Self, (F, AllocError)>
+    where
+        F: Future<Output = T> + Send + 'a,
+    {
+        Inner::try_new(future).map(Self)
+    }
+
+    /// Replace the future currently stored in this box.
+    ///
+    /// This reallocates only if the provided future no longer fits in the
+    /// box's current allocation.
+    pub fn set<F>(&mut self, future: F)
+    where
+        F: Future<Output = T> + Send + 'a,
+    {
+        self.0.set(future);
+    }
+
+    /// Replace the future currently stored in this box.
+    ///
+    /// This function never reallocates, but returns an error if the provided
+    /// future no longer fits in the box's current allocation.
+    pub fn try_set<F>(&mut self, future: F) -> Result<(), F>
+    where
+        F: Future<Output = T> + Send + 'a,
+    {
+        self.0.try_set(future)
+    }
+
+    /// Replace the future currently stored in this box, without aborting on
+    /// allocation failure.
+    ///
+    /// This reallocates only if the provided future no longer fits in the
+    /// box's current allocation. If the reallocation is required and the
+    /// global allocator fails to satisfy it, this box is left untouched and
+    /// the future is handed back to the caller alongside an [`AllocError`].
+    pub fn try_new_set<F>(&mut self, future: F) -> Result<(), (F, AllocError)>
+    where
+        F: Future<Output = T> + Send + 'a,
+    {
+        self.0.try_new_set(future)
+    }
+
+    /// Get a pinned reference to the underlying future.
+    pub fn get_pin(&mut self) -> Pin<&mut (dyn Future<Output = T> + 'a)> {
+        self.0.get_pin()
+    }
+
     /// Poll the future stored inside this box.
     pub fn poll(&mut self, cx: &mut Context<'_>) -> Poll<T> {
-        self.get_pin().poll(cx)
+        self.0.poll(cx)
+    }
+
+    /// Returns `true` if the most recent call to `poll` returned
+    /// `Poll::Ready`.
+    ///
+    /// A scheduler can use this to tell, without polling again, that this
+    /// box is ready to be refilled with a new future via `set`/`try_set`.
+    pub fn is_finished(&self) -> bool {
+        self.0.is_finished()
+    }
+
+    /// Poll the stored future and, if it has just completed, install `next`
+    /// before returning the completed value, so the box is refilled without
+    /// an intervening `Poll::Pending`. As with `set`, `next` reuses the
+    /// current allocation if it fits; otherwise the box reallocates.
+    ///
+    /// If the stored future is still pending, `next` is dropped without
+    /// being installed and the stored future is left in place to be polled
+    /// again.
+    pub fn set_and_return_completed<F>(&mut self, cx: &mut Context<'_>, next: F) -> Poll<T>
+    where
+        F: Future<Output = T> + Send + 'a,
+    {
+        self.0.set_and_return_completed(cx, next)
     }
 }
 
-impl<T> Future for ReusableBoxFuture<T> {
+impl<'a, T> Future for ReusableBoxFuture<'a, T> {
     type Output = T;
 
     /// Poll the future stored inside this box.
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
-        Pin::into_inner(self).get_pin().poll(cx)
+        Pin::into_inner(self).0.poll(cx)
     }
 }
 
-// The future stored inside ReusableBoxFuture<T> must be Send.
-unsafe impl<T> Send for ReusableBoxFuture<T> {}
+// Every constructor and setter above requires `F: Send`, so the future
+// stored inside `ReusableBoxFuture<T>` is always `Send`.
+unsafe impl<T> Send for ReusableBoxFuture<'_, T> {}
 
-// The only method called on self.boxed is poll, which takes &mut self, so this
-// struct being Sync does not permit any invalid access to the Future, even if
-// the future is not Sync.
-unsafe impl<T> Sync for ReusableBoxFuture<T> {}
+// The only method called on the boxed future is poll, which takes &mut self,
+// so this struct being Sync does not permit any invalid access to the
+// future, even if the future is not Sync.
+unsafe impl<T> Sync for ReusableBoxFuture<'_, T> {}
 
 // Just like a Pin<Box<dyn Future>> is always Unpin, so is this type.
-impl<T> Unpin for ReusableBoxFuture<T> {}
+impl<T> Unpin for ReusableBoxFuture<'_, T> {}
 
-impl<T> Drop for ReusableBoxFuture<T> {
-    fn drop(&mut self) {
-        unsafe {
-            drop(Box::from_raw(self.boxed.as_ptr()));
-        }
+impl<T> fmt::Debug for ReusableBoxFuture<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReusableBoxFuture").finish()
     }
 }
 
-impl<T> fmt::Debug for ReusableBoxFuture<T> {
+/// A reusable `Pin<Box<dyn Future<Output = T> + 'a>>` for futures that are
+/// not `Send`.
+///
+/// This is the `!Send` counterpart to [`ReusableBoxFuture`], for use on a
+/// single-threaded or current-thread executor. It shares the same reuse
+/// behavior (see [`ReusableBoxFuture`]'s docs) but places no `Send` bound on
+/// the stored future, and it is itself neither `Send` nor `Sync`.
+pub struct LocalReusableBoxFuture<'a, T>(Inner<'a, T>);
+
+impl<'a, T> LocalReusableBoxFuture<'a, T> {
+    /// Create a new `LocalReusableBoxFuture<T>` containing the provided
+    /// future.
+    pub fn new<F>(future: F) -> Self
+    where
+        F: Future<Output = T> + 'a,
+    {
+        Self(Inner::new(future))
+    }
+
+    /// Create a new `LocalReusableBoxFuture<T>` containing the provided
+    /// future, without aborting on allocation failure.
+    ///
+    /// If the global allocator fails to satisfy the allocation, the future is
+    /// handed back to the caller alongside an [`AllocError`].
+    pub fn try_new<F>(future: F) -> Result<Self, (F, AllocError)>
+    where
+        F: Future<Output = T> + 'a,
+    {
+        Inner::try_new(future).map(Self)
+    }
+
+    /// Replace the future currently stored in this box.
+    ///
+    /// This reallocates only if the provided future no longer fits in the
+    /// box's current allocation.
+    pub fn set<F>(&mut self, future: F)
+    where
+        F: Future<Output = T> + 'a,
+    {
+        self.0.set(future);
+    }
+
+    /// Replace the future currently stored in this box.
+    ///
+    /// This function never reallocates, but returns an error if the provided
+    /// future no longer fits in the box's current allocation.
+    pub fn try_set<F>(&mut self, future: F) -> Result<(), F>
+    where
+        F: Future<Output = T> + 'a,
+    {
+        self.0.try_set(future)
+    }
+
+    /// Replace the future currently stored in this box, without aborting on
+    /// allocation failure.
+    ///
+    /// This reallocates only if the provided future no longer fits in the
+    /// box's current allocation. If the reallocation is required and the
+    /// global allocator fails to satisfy it, this box is left untouched and
+    /// the future is handed back to the caller alongside an [`AllocError`].
+    pub fn try_new_set<F>(&mut self, future: F) -> Result<(), (F, AllocError)>
+    where
+        F: Future<Output = T> + 'a,
+    {
+        self.0.try_new_set(future)
+    }
+
+    /// Get a pinned reference to the underlying future.
+    pub fn get_pin(&mut self) -> Pin<&mut (dyn Future<Output = T> + 'a)> {
+        self.0.get_pin()
+    }
+
+    /// Poll the future stored inside this box.
+    pub fn poll(&mut self, cx: &mut Context<'_>) -> Poll<T> {
+        self.0.poll(cx)
+    }
+
+    /// Returns `true` if the most recent call to `poll` returned
+    /// `Poll::Ready`.
+    ///
+    /// A scheduler can use this to tell, without polling again, that this
+    /// box is ready to be refilled with a new future via `set`/`try_set`.
+    pub fn is_finished(&self) -> bool {
+        self.0.is_finished()
+    }
+
+    /// Poll the stored future and, if it has just completed, install `next`
+    /// before returning the completed value, so the box is refilled without
+    /// an intervening `Poll::Pending`. As with `set`, `next` reuses the
+    /// current allocation if it fits; otherwise the box reallocates.
+    ///
+    /// If the stored future is still pending, `next` is dropped without
+    /// being installed and the stored future is left in place to be polled
+    /// again.
+    pub fn set_and_return_completed<F>(&mut self, cx: &mut Context<'_>, next: F) -> Poll<T>
+    where
+        F: Future<Output = T> + 'a,
+    {
+        self.0.set_and_return_completed(cx, next)
+    }
+}
+
+impl<'a, T> Future for LocalReusableBoxFuture<'a, T> {
+    type Output = T;
+
+    /// Poll the future stored inside this box.
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        Pin::into_inner(self).0.poll(cx)
+    }
+}
+
+// Just like a Pin<Box<dyn Future>> is always Unpin, so is this type.
+impl<T> Unpin for LocalReusableBoxFuture<'_, T> {}
+
+impl<T> fmt::Debug for LocalReusableBoxFuture<'_, T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("ReusableBoxFuture").finish()
+        f.debug_struct("LocalReusableBoxFuture").finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::{GlobalAlloc, System};
+    use std::cell::Cell;
+    use std::task::Waker;
+
+    /// A future that is immediately ready with a stored value, padded out to
+    /// `PAD` extra bytes so tests can control its `Layout` independently of
+    /// its output type.
+    #[allow(dead_code)]
+    struct ReadyFuture<T, const PAD: usize = 0> {
+        value: Option<T>,
+        pad: [u8; PAD],
+    }
+
+    impl<T, const PAD: usize> ReadyFuture<T, PAD> {
+        fn new(value: T) -> Self {
+            Self {
+                value: Some(value),
+                pad: [0; PAD],
+            }
+        }
+    }
+
+    impl<T: Unpin, const PAD: usize> Future for ReadyFuture<T, PAD> {
+        type Output = T;
+
+        fn poll(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<T> {
+            Poll::Ready(self.value.take().expect("ReadyFuture polled after completion"))
+        }
+    }
+
+    thread_local! {
+        /// When set, the next allocation made by this thread through
+        /// `FailingAllocator` fails and returns a null pointer, simulating
+        /// allocator exhaustion. Thread-local (rather than global) so that
+        /// tests exercising this run safely alongside unrelated tests on
+        /// other threads.
+        static FAIL_NEXT_ALLOC: Cell<bool> = const { Cell::new(false) };
+    }
+
+    /// Wraps the system allocator so a single test thread can force the next
+    /// allocation to fail, without affecting other threads.
+    struct FailingAllocator;
+
+    unsafe impl GlobalAlloc for FailingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            if FAIL_NEXT_ALLOC.with(Cell::take) {
+                return ptr::null_mut();
+            }
+            unsafe { System.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            unsafe { System.dealloc(ptr, layout) }
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: FailingAllocator = FailingAllocator;
+
+    #[test]
+    fn try_new_succeeds_for_an_ordinary_future() {
+        let result = ReusableBoxFuture::try_new(ReadyFuture::<u8>::new(5));
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn try_new_surfaces_alloc_error_and_hands_the_future_back() {
+        let future = ReadyFuture::<u8, 128>::new(5);
+
+        FAIL_NEXT_ALLOC.with(|f| f.set(true));
+        match ReusableBoxFuture::try_new(future) {
+            Ok(_) => panic!("expected the simulated allocation failure to surface"),
+            Err((returned, AllocError)) => assert_eq!(returned.value, Some(5)),
+        }
+    }
+
+    #[test]
+    fn try_new_set_surfaces_alloc_error_without_disturbing_the_existing_future() {
+        let mut b = ReusableBoxFuture::new(ReadyFuture::<u8>::new(1));
+
+        FAIL_NEXT_ALLOC.with(|f| f.set(true));
+        match b.try_new_set(ReadyFuture::<u8, 128>::new(2)) {
+            Ok(()) => panic!("expected the simulated allocation failure to surface"),
+            Err((returned, AllocError)) => assert_eq!(returned.value, Some(2)),
+        }
+
+        let mut cx = Context::from_waker(Waker::noop());
+        assert_eq!(
+            b.poll(&mut cx),
+            Poll::Ready(1),
+            "a failed try_new_set must leave the original future usable"
+        );
+    }
+
+    /// Address of the future currently stored in `b`, for checking whether a
+    /// `set` reused the allocation or reallocated.
+    fn stored_at<T>(b: &mut ReusableBoxFuture<'_, T>) -> *const () {
+        b.0.boxed.as_ptr() as *const ()
+    }
+
+    #[test]
+    fn set_reuses_the_allocation_when_the_new_future_fits() {
+        let mut b = ReusableBoxFuture::new(ReadyFuture::<u8, 64>::new(1));
+        let before = stored_at(&mut b);
+
+        b.set(ReadyFuture::<u8, 8>::new(2));
+
+        assert_eq!(
+            before,
+            stored_at(&mut b),
+            "a smaller future that fits the existing allocation should reuse it"
+        );
+        let mut cx = Context::from_waker(Waker::noop());
+        assert_eq!(b.poll(&mut cx), Poll::Ready(2));
+    }
+
+    #[test]
+    fn set_reallocates_when_the_new_future_does_not_fit() {
+        let mut b = ReusableBoxFuture::new(ReadyFuture::<u8, 8>::new(1));
+        let before = stored_at(&mut b);
+
+        b.set(ReadyFuture::<u8, 64>::new(2));
+
+        assert_ne!(
+            before,
+            stored_at(&mut b),
+            "a future too large for the existing allocation must reallocate"
+        );
+        let mut cx = Context::from_waker(Waker::noop());
+        assert_eq!(b.poll(&mut cx), Poll::Ready(2));
+    }
+
+    #[test]
+    fn try_set_rejects_a_future_that_does_not_fit_without_reallocating() {
+        let mut b = ReusableBoxFuture::new(ReadyFuture::<u8, 8>::new(1));
+        let before = stored_at(&mut b);
+
+        let rejected = b
+            .try_set(ReadyFuture::<u8, 64>::new(2))
+            .expect_err("a future larger than the current allocation must be rejected");
+        assert_eq!(rejected.value, Some(2));
+        assert_eq!(before, stored_at(&mut b));
+
+        let mut cx = Context::from_waker(Waker::noop());
+        assert_eq!(b.poll(&mut cx), Poll::Ready(1));
+    }
+
+    #[test]
+    fn zero_sized_future_round_trips_without_a_real_allocation() {
+        struct UnitFuture;
+
+        impl Future for UnitFuture {
+            type Output = ();
+
+            fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<()> {
+                Poll::Ready(())
+            }
+        }
+
+        let mut b = ReusableBoxFuture::new(UnitFuture);
+        assert_eq!(b.0.alloc_layout.size(), 0);
+
+        let mut cx = Context::from_waker(Waker::noop());
+        assert_eq!(b.poll(&mut cx), Poll::Ready(()));
+        assert!(b.is_finished());
+
+        // Swapping in another zero-sized future must not call the allocator.
+        b.set(UnitFuture);
+        assert_eq!(b.0.alloc_layout.size(), 0);
+        assert_eq!(b.poll(&mut cx), Poll::Ready(()));
+    }
+
+    fn assert_send<T: Send>() {}
+    fn assert_sync<T: Sync>() {}
+
+    #[test]
+    fn reusable_box_future_is_send_and_sync() {
+        assert_send::<ReusableBoxFuture<'static, u8>>();
+        assert_sync::<ReusableBoxFuture<'static, u8>>();
+    }
+
+    #[test]
+    fn local_reusable_box_future_is_not_send() {
+        // A type is `Send` if it has any applicable `Send` impl. This relies
+        // on a blanket impl applying to every type, plus a second impl that
+        // only applies to `Send` types: if `LocalReusableBoxFuture` were
+        // `Send`, both impls would apply and the call below would be
+        // ambiguous, failing to compile. It only compiles because
+        // `LocalReusableBoxFuture` is *not* `Send`.
+        trait AmbiguousIfSend<A> {
+            fn assert() {}
+        }
+        impl<T: ?Sized> AmbiguousIfSend<()> for T {}
+        struct IsSend;
+        impl<T: ?Sized + Send> AmbiguousIfSend<IsSend> for T {}
+
+        let _ = <LocalReusableBoxFuture<'static, u8> as AmbiguousIfSend<_>>::assert;
+    }
+
+    #[test]
+    fn local_reusable_box_future_can_store_a_non_send_future() {
+        use std::rc::Rc;
+
+        struct RcFuture(Option<Rc<i32>>);
+
+        impl Future for RcFuture {
+            type Output = Rc<i32>;
+
+            fn poll(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+                Poll::Ready(self.0.take().expect("RcFuture polled after completion"))
+            }
+        }
+
+        let mut b = LocalReusableBoxFuture::new(RcFuture(Some(Rc::new(42))));
+        let mut cx = Context::from_waker(Waker::noop());
+        match Future::poll(Pin::new(&mut b), &mut cx) {
+            Poll::Ready(value) => assert_eq!(*value, 42),
+            Poll::Pending => panic!("expected the future to resolve immediately"),
+        }
+    }
+
+    /// A future that reports `Pending` once, then resolves to the stored
+    /// value on the next poll.
+    struct PendingOnceFuture<T> {
+        value: Option<T>,
+        polled: bool,
+    }
+
+    impl<T: Unpin> Future for PendingOnceFuture<T> {
+        type Output = T;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+            if self.polled {
+                Poll::Ready(self.value.take().expect("polled after completion"))
+            } else {
+                self.polled = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    #[test]
+    fn is_finished_tracks_completion_through_the_inherent_poll() {
+        let mut b = ReusableBoxFuture::new(ReadyFuture::<u8>::new(7));
+        let mut cx = Context::from_waker(Waker::noop());
+
+        assert!(!b.is_finished());
+        assert_eq!(b.poll(&mut cx), Poll::Ready(7));
+        assert!(b.is_finished());
+    }
+
+    #[test]
+    fn is_finished_tracks_completion_through_the_future_trait_impl() {
+        let mut b = ReusableBoxFuture::new(ReadyFuture::<u8>::new(7));
+        let mut cx = Context::from_waker(Waker::noop());
+
+        assert!(!b.is_finished());
+        assert_eq!(Future::poll(Pin::new(&mut b), &mut cx), Poll::Ready(7));
+        assert!(b.is_finished());
+    }
+
+    #[test]
+    fn local_is_finished_tracks_completion_through_the_future_trait_impl() {
+        let mut b = LocalReusableBoxFuture::new(ReadyFuture::<u8>::new(7));
+        let mut cx = Context::from_waker(Waker::noop());
+
+        assert!(!b.is_finished());
+        assert_eq!(Future::poll(Pin::new(&mut b), &mut cx), Poll::Ready(7));
+        assert!(b.is_finished());
+    }
+
+    #[test]
+    fn set_and_return_completed_installs_the_next_future_in_one_call() {
+        let mut b = ReusableBoxFuture::new(ReadyFuture::<u8>::new(1));
+        let mut cx = Context::from_waker(Waker::noop());
+
+        let result = b.set_and_return_completed(&mut cx, ReadyFuture::<u8>::new(2));
+
+        assert_eq!(result, Poll::Ready(1));
+        assert!(
+            !b.is_finished(),
+            "the freshly installed future has not been polled yet"
+        );
+        assert_eq!(b.poll(&mut cx), Poll::Ready(2));
+    }
+
+    #[test]
+    fn set_and_return_completed_leaves_a_pending_future_in_place() {
+        let mut b = ReusableBoxFuture::new(PendingOnceFuture {
+            value: Some(1u8),
+            polled: false,
+        });
+        let mut cx = Context::from_waker(Waker::noop());
+
+        let result = b.set_and_return_completed(&mut cx, ReadyFuture::<u8>::new(99));
+
+        assert_eq!(result, Poll::Pending);
+        assert!(!b.is_finished());
+        // The second poll must resolve the original future, not the `next`
+        // future that was dropped without being installed.
+        assert_eq!(b.poll(&mut cx), Poll::Ready(1));
     }
 }